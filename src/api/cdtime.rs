@@ -8,68 +8,171 @@
 //! very close to nanoseconds. *The* big advantage of storing time in this manner is that comparing
 //! times and calculating differences is as simple as it is with `time_t`, i.e. a simple integer
 //! comparison / subtraction works.
+//!
+//! That last point is also why `cdtime_t` is used for interval arithmetic: subtracting two
+//! `cdtime_t` values and interpreting the (wrapping) result as signed gives you a duration. So
+//! `CdTime` stores signed epoch nanoseconds rather than an unsigned count, and the conversions that
+//! could be handed a value collectd's packed format can't represent are `TryFrom`, returning
+//! `Err` instead of silently wrapping.
+//!
+//! (`From` and `TryFrom` for the same pair of types can't coexist: the standard library provides a
+//! blanket `TryFrom` for anything with a `From`, so defining both ourselves would conflict. Callers
+//! that would rather saturate than handle a `Result` can use [`CdTime::saturating_from_datetime`],
+//! [`CdTime::saturating_from_duration`], or [`CdTime::saturating_to_cdtime_t`] instead.)
 
 use crate::bindings::cdtime_t;
 use chrono::prelude::*;
 use chrono::Duration;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
 
 /// `CdTime` allows for ergonomic interop between collectd's `cdtime_t` and chrono's `Duration` and
-/// `DateTime`. The single field represents epoch nanoseconds.
+/// `DateTime`. The single field represents signed epoch nanoseconds.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CdTime(pub i64);
+
+/// Returned when a value can't be represented without loss: either it doesn't fit in signed
+/// epoch nanoseconds (chrono's own ~292 year `Duration` limit), or its whole-seconds component
+/// doesn't fit in the signed range collectd's packed 2<sup>-30</sup> second format can hold.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct CdTime(pub u64);
+pub struct CdTimeRangeError;
+
+impl fmt::Display for CdTimeRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("value is out of range to be represented as a CdTime / cdtime_t")
+    }
+}
+
+impl error::Error for CdTimeRangeError {}
+
+/// Whole-seconds bounds used when saturating to collectd's packed 2<sup>-30</sup> second
+/// `cdtime_t` format: the top bits hold signed seconds, shifted left 30, so the usable range is
+/// narrower than a bare `i64`. `MIN` sits one second inside the format's lower limit
+/// (`i64::MIN >> 30`), so the saturation range is symmetric around zero.
+const MAX_CDTIME_SECONDS: i64 = i64::MAX >> 30;
+const MIN_CDTIME_SECONDS: i64 = (i64::MIN >> 30) + 1;
+const MAX_REPRESENTABLE_NANOS: i64 = MAX_CDTIME_SECONDS * 1_000_000_000 + 999_999_999;
+const MIN_REPRESENTABLE_NANOS: i64 = MIN_CDTIME_SECONDS * 1_000_000_000;
 
-impl<Tz: TimeZone> From<DateTime<Tz>> for CdTime {
-    fn from(dt: DateTime<Tz>) -> Self {
-        let sec_nanos = (dt.timestamp() as u64) * 1_000_000_000;
-        let nanos = u64::from(dt.timestamp_subsec_nanos());
-        CdTime(sec_nanos + nanos)
+impl<Tz: TimeZone> TryFrom<DateTime<Tz>> for CdTime {
+    type Error = CdTimeRangeError;
+
+    fn try_from(dt: DateTime<Tz>) -> Result<Self, Self::Error> {
+        let secs = dt.timestamp();
+        let nanos = i64::from(dt.timestamp_subsec_nanos());
+        secs.checked_mul(1_000_000_000)
+            .and_then(|sec_nanos| sec_nanos.checked_add(nanos))
+            .map(CdTime)
+            .ok_or(CdTimeRangeError)
     }
 }
 
 impl From<CdTime> for DateTime<Utc> {
     fn from(v: CdTime) -> DateTime<Utc> {
         let CdTime(ns) = v;
-        let secs = ns / 1_000_000_000;
-        let left = ns % 1_000_000_000;
-        Utc.timestamp(secs as i64, left as u32)
+        // `div_euclid`/`rem_euclid` (rather than plain `/`/`%`) keep the sub-second component
+        // non-negative even when `ns` itself is negative, matching what `DateTime` expects.
+        let secs = ns.div_euclid(1_000_000_000);
+        let nanos = ns.rem_euclid(1_000_000_000);
+        Utc.timestamp(secs, nanos as u32)
     }
 }
 
-impl From<Duration> for CdTime {
-    fn from(d: Duration) -> Self {
-        CdTime(d.num_nanoseconds().unwrap() as u64)
+impl TryFrom<Duration> for CdTime {
+    type Error = CdTimeRangeError;
+
+    fn try_from(d: Duration) -> Result<Self, Self::Error> {
+        d.num_nanoseconds().map(CdTime).ok_or(CdTimeRangeError)
     }
 }
 
 impl From<CdTime> for Duration {
     fn from(v: CdTime) -> Self {
         let CdTime(ns) = v;
-        Duration::nanoseconds(ns as i64)
+        Duration::nanoseconds(ns)
     }
 }
 
 impl From<cdtime_t> for CdTime {
+    /// Interprets `d`'s bit pattern as signed, per the module docs above. This is correct for any
+    /// value collectd itself will produce (a wall-clock timestamp or a `cdtime_t` difference)
+    /// until roughly the year 2242, when an absolute timestamp's top bit starts being set and this
+    /// would misread it as a negative/pre-epoch value.
     fn from(d: cdtime_t) -> Self {
         CdTime(collectd_to_nanos(d))
     }
 }
 
-impl From<CdTime> for cdtime_t {
-    fn from(d: CdTime) -> Self {
-        let CdTime(x) = d;
-        nanos_to_collectd(x)
+impl TryFrom<CdTime> for cdtime_t {
+    type Error = CdTimeRangeError;
+
+    fn try_from(d: CdTime) -> Result<Self, Self::Error> {
+        nanos_to_collectd(d.0)
+    }
+}
+
+impl CdTime {
+    /// Saturating counterpart to `TryFrom<DateTime<Tz>>`, for callers that would rather clamp to
+    /// the representable range than thread a `Result` through for a date this extreme.
+    pub fn saturating_from_datetime<Tz: TimeZone>(dt: DateTime<Tz>) -> Self {
+        let before_epoch = dt.timestamp() < 0;
+        match CdTime::try_from(dt) {
+            Ok(CdTime(ns)) => CdTime(ns.clamp(MIN_REPRESENTABLE_NANOS, MAX_REPRESENTABLE_NANOS)),
+            Err(_) if before_epoch => CdTime(MIN_REPRESENTABLE_NANOS),
+            Err(_) => CdTime(MAX_REPRESENTABLE_NANOS),
+        }
+    }
+
+    /// Saturating counterpart to `TryFrom<Duration>`. Only spans beyond chrono's own ~292 year
+    /// nanosecond range hit the clamp.
+    pub fn saturating_from_duration(d: Duration) -> Self {
+        let negative = d < Duration::zero();
+        CdTime::try_from(d).unwrap_or(if negative {
+            CdTime(MIN_REPRESENTABLE_NANOS)
+        } else {
+            CdTime(MAX_REPRESENTABLE_NANOS)
+        })
+    }
+
+    /// Saturating counterpart to `TryFrom<CdTime> for cdtime_t`.
+    pub fn saturating_to_cdtime_t(self) -> cdtime_t {
+        cdtime_t::try_from(self).unwrap_or_else(|_| {
+            let saturated = if self.0 < 0 {
+                MIN_REPRESENTABLE_NANOS
+            } else {
+                MAX_REPRESENTABLE_NANOS
+            };
+            nanos_to_collectd(saturated).expect("saturated bound is always representable")
+        })
     }
 }
 
-/// Convert epoch nanoseconds into collectd's 2<sup>-30</sup> second resolution
-pub fn nanos_to_collectd(nanos: u64) -> cdtime_t {
-    ((nanos / 1_000_000_000) << 30)
-        | ((((nanos % 1_000_000_000) << 30) + 500_000_000) / 1_000_000_000)
+/// Convert (possibly negative) epoch nanoseconds into collectd's 2<sup>-30</sup> second
+/// resolution, keeping the lossless rounding on the positive path. Returns `Err` if `nanos`
+/// doesn't fit in the signed range collectd's packed format can represent.
+pub fn nanos_to_collectd(nanos: i64) -> Result<cdtime_t, CdTimeRangeError> {
+    let seconds = nanos.div_euclid(1_000_000_000);
+    let frac_nanos = nanos.rem_euclid(1_000_000_000);
+    let frac = (frac_nanos * (1 << 30) + 500_000_000) / 1_000_000_000;
+
+    seconds
+        .checked_mul(1 << 30)
+        .and_then(|sec_part| sec_part.checked_add(frac))
+        .map(|cd| cd as cdtime_t)
+        .ok_or(CdTimeRangeError)
 }
 
-/// Convert collectd's 2^-30 second resolution into epoch nanoseconds
-fn collectd_to_nanos(cd: cdtime_t) -> u64 {
-    ((cd >> 30) * 1_000_000_000) + (((cd & 0x3fff_ffff) * 1_000_000_000 + (1 << 29)) >> 30)
+/// Convert collectd's 2^-30 second resolution into (possibly negative) epoch nanoseconds.
+///
+/// Reinterprets `cd`'s bit pattern as signed, which is what makes negative interval round-tripping
+/// work; see the `From<cdtime_t> for CdTime` doc comment for the absolute-timestamp caveat this
+/// implies (values with the top bit set are read as negative).
+fn collectd_to_nanos(cd: cdtime_t) -> i64 {
+    let cd = cd as i64;
+    let seconds = cd >> 30;
+    let frac = cd & 0x3fff_ffff;
+    seconds * 1_000_000_000 + ((frac * 1_000_000_000 + (1 << 29)) >> 30)
 }
 
 #[cfg(test)]
@@ -80,9 +183,18 @@ mod tests {
     fn test_nanos_to_collectd() {
         // Taken from utils_time_test.c
 
-        assert_eq!(nanos_to_collectd(1439981652801860766), 1546168526406004689);
-        assert_eq!(nanos_to_collectd(1439981836985281914), 1546168724171447263);
-        assert_eq!(nanos_to_collectd(1439981880053705608), 1546168770415815077);
+        assert_eq!(
+            nanos_to_collectd(1439981652801860766),
+            Ok(1546168526406004689)
+        );
+        assert_eq!(
+            nanos_to_collectd(1439981836985281914),
+            Ok(1546168724171447263)
+        );
+        assert_eq!(
+            nanos_to_collectd(1439981880053705608),
+            Ok(1546168770415815077)
+        );
     }
 
     #[test]
@@ -94,14 +206,14 @@ mod tests {
 
     #[test]
     fn test_collectd_to_duration() {
-        let v: cdtime_t = nanos_to_collectd(1_000_000_000);
+        let v: cdtime_t = nanos_to_collectd(1_000_000_000).unwrap();
         let dur = Duration::from(CdTime::from(v));
         assert_eq!(dur.num_seconds(), 1);
     }
 
     #[test]
     fn test_collectd_to_datetime() {
-        let v: cdtime_t = nanos_to_collectd(1_000_000_000);
+        let v: cdtime_t = nanos_to_collectd(1_000_000_000).unwrap();
         let dt: DateTime<Utc> = CdTime::from(v).into();
         assert_eq!(Utc.ymd(1970, 1, 1).and_hms(0, 0, 1), dt);
     }
@@ -109,7 +221,89 @@ mod tests {
     #[test]
     fn test_datetime_to_collectd() {
         let dt = Utc.ymd(1970, 1, 1).and_hms(0, 0, 1);
-        let cd = CdTime::from(dt);
+        let cd = CdTime::try_from(dt).unwrap();
         assert_eq!(cd.0, 1_000_000_000);
     }
+
+    #[test]
+    fn test_negative_duration_round_trips() {
+        let d = Duration::seconds(-5) + Duration::milliseconds(-250);
+        let cd = CdTime::try_from(d).unwrap();
+        assert_eq!(cd.0, -5_250_000_000);
+
+        let back: Duration = cd.into();
+        assert_eq!(back, d);
+    }
+
+    #[test]
+    fn test_pre_epoch_datetime_round_trips() {
+        let dt = Utc.ymd(1960, 1, 1).and_hms(0, 0, 0);
+        let cd = CdTime::try_from(dt).unwrap();
+        assert!(cd.0 < 0);
+
+        let back: DateTime<Utc> = cd.into();
+        assert_eq!(back, dt);
+    }
+
+    #[test]
+    fn test_negative_cdtime_round_trips_through_collectd_format() {
+        let cd = CdTime(-5_250_000_000);
+        let raw = cdtime_t::try_from(cd).unwrap();
+        let back = CdTime::from(raw);
+        assert_eq!(back, cd);
+    }
+
+    #[test]
+    fn test_duration_beyond_chrono_range_is_rejected() {
+        // chrono's `Duration` can represent spans that don't fit in nanoseconds; `num_nanoseconds`
+        // already documents this as returning `None`, so `TryFrom` must propagate that instead of
+        // panicking like the old `unwrap`-based `From` impl did.
+        let d = Duration::max_value();
+        assert_eq!(CdTime::try_from(d), Err(CdTimeRangeError));
+    }
+
+    #[test]
+    fn test_seconds_out_of_collectd_range_is_rejected() {
+        // One second past the largest whole-seconds value collectd's packed format can hold in
+        // the significand after shifting left 30 bits.
+        let nanos = ((i64::MAX >> 30) + 1).saturating_mul(1_000_000_000);
+        assert_eq!(nanos_to_collectd(nanos), Err(CdTimeRangeError));
+    }
+
+    #[test]
+    fn test_saturating_from_duration() {
+        assert_eq!(
+            CdTime::saturating_from_duration(Duration::max_value()).0,
+            MAX_REPRESENTABLE_NANOS
+        );
+        assert_eq!(
+            CdTime::saturating_from_duration(Duration::min_value()).0,
+            MIN_REPRESENTABLE_NANOS
+        );
+        assert_eq!(
+            CdTime::saturating_from_duration(Duration::seconds(5)).0,
+            5_000_000_000
+        );
+    }
+
+    #[test]
+    fn test_saturating_from_datetime() {
+        let far_future = Utc.ymd(9999, 12, 31).and_hms(0, 0, 0);
+        assert_eq!(
+            CdTime::saturating_from_datetime(far_future).0,
+            MAX_REPRESENTABLE_NANOS
+        );
+
+        let dt = Utc.ymd(1970, 1, 1).and_hms(0, 0, 1);
+        assert_eq!(CdTime::saturating_from_datetime(dt).0, 1_000_000_000);
+    }
+
+    #[test]
+    fn test_saturating_to_cdtime_t() {
+        let out_of_range = CdTime(MAX_REPRESENTABLE_NANOS.saturating_add(1_000_000_000));
+        assert_eq!(
+            out_of_range.saturating_to_cdtime_t(),
+            nanos_to_collectd(MAX_REPRESENTABLE_NANOS).unwrap()
+        );
+    }
 }
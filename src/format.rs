@@ -0,0 +1,158 @@
+//! # Line protocol
+//!
+//! Serializes a [`ValueList`] into [InfluxDB line
+//! protocol](https://docs.influxdata.com/influxdb/v1/write_protocols/line_protocol_reference/),
+//! so write plugins can ship straight to InfluxDB without hand-rolling the format (the way the
+//! `write_graphite` example hand-rolls Graphite's plaintext protocol).
+//!
+//! The measurement is `plugin`, the tag set is built from `host`, `plugin_instance`, `type_`, and
+//! `type_instance`, and each `ValueListItem` becomes one field keyed by its name. Tag keys/values
+//! and field keys are escaped per the line protocol spec (commas, spaces, and equals signs are
+//! backslash-escaped). Collectd's `Value` has no string variant, so there is no string-field
+//! escaping/quoting path to implement here.
+//!
+//! Each line is terminated with `\n`, matching line protocol's one-line-per-point convention and
+//! [`sink::WriteSink`](crate::sink::WriteSink)'s contract of batching already-delimited byte runs
+//! without inserting separators of its own.
+
+use crate::api::cdtime::CdTime;
+use crate::api::{Value, ValueList};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+/// Serializes `list` to a single, `\n`-terminated InfluxDB line protocol line, ready to hand
+/// straight to [`sink::WriteSink::push`](crate::sink::WriteSink::push).
+///
+/// # Panics
+///
+/// Panics where [`write_line_protocol`] returns an error: a NaN or infinite gauge value, or a
+/// timestamp that doesn't fit in signed epoch nanoseconds.
+pub fn to_line_protocol(list: &ValueList<'_>) -> String {
+    let mut buf = Vec::new();
+    // `Vec<u8>`'s `Write` impl cannot fail, so the only errors here are values that line
+    // protocol can't represent.
+    write_line_protocol(&mut buf, list).expect("value list is not representable as line protocol");
+    String::from_utf8(buf).expect("line protocol output is always valid UTF-8")
+}
+
+/// Streaming form of [`to_line_protocol`]: writes a single `\n`-terminated line protocol line to
+/// `w`.
+pub fn write_line_protocol<W: Write>(w: &mut W, list: &ValueList<'_>) -> io::Result<()> {
+    write!(w, "{}", escape_measurement(list.plugin))?;
+
+    write_tag(w, "host", list.host)?;
+    if let Some(instance) = list.plugin_instance {
+        write_tag(w, "plugin_instance", instance)?;
+    }
+    write_tag(w, "type", list.type_)?;
+    if let Some(type_instance) = list.type_instance {
+        write_tag(w, "type_instance", type_instance)?;
+    }
+
+    w.write_all(b" ")?;
+    for (i, item) in list.values.iter().enumerate() {
+        if i != 0 {
+            w.write_all(b",")?;
+        }
+        write!(w, "{}=", escape_key(item.name))?;
+        write_field_value(w, item.value)?;
+    }
+
+    let nanos = CdTime::try_from(list.time)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .0;
+    writeln!(w, " {}", nanos)?;
+
+    Ok(())
+}
+
+fn write_tag<W: Write>(w: &mut W, key: &str, value: &str) -> io::Result<()> {
+    write!(w, ",{}={}", escape_key(key), escape_key(value))
+}
+
+fn write_field_value<W: Write>(w: &mut W, value: Value) -> io::Result<()> {
+    match value {
+        // Collectd uses NaN for "no data", but line protocol floats must be finite.
+        Value::Gauge(v) if !v.is_finite() => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not representable as a line protocol field value", v),
+        )),
+        Value::Gauge(v) => write!(w, "{}", v),
+        Value::Derive(v) => write!(w, "{}i", v),
+        // `Counter`/`Absolute` are `u64`, but line protocol's signed `i` suffix tops out at
+        // `i64::MAX`; fall back to the `u` (unsigned integer) suffix above that.
+        Value::Counter(v) | Value::Absolute(v) => write_unsigned_field(w, v),
+    }
+}
+
+fn write_unsigned_field<W: Write>(w: &mut W, v: u64) -> io::Result<()> {
+    if v <= i64::MAX as u64 {
+        write!(w, "{}i", v)
+    } else {
+        write!(w, "{}u", v)
+    }
+}
+
+/// Escapes a measurement name: commas and spaces must be backslash-escaped.
+fn escape_measurement(s: &str) -> Cow<'_, str> {
+    escape(s, |c| c == ',' || c == ' ')
+}
+
+/// Escapes a tag key, tag value, or field key: commas, spaces, and equals signs must be
+/// backslash-escaped.
+fn escape_key(s: &str) -> Cow<'_, str> {
+    escape(s, |c| c == ',' || c == ' ' || c == '=')
+}
+
+fn escape(s: &str, needs_escaping: impl Fn(char) -> bool) -> Cow<'_, str> {
+    if !s.chars().any(&needs_escaping) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if needs_escaping(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_measurement() {
+        assert_eq!(escape_measurement("cpu"), "cpu");
+        assert_eq!(escape_measurement("cpu load"), "cpu\\ load");
+        assert_eq!(escape_measurement("cpu,load"), "cpu\\,load");
+    }
+
+    #[test]
+    fn test_escape_key() {
+        assert_eq!(escape_key("host"), "host");
+        assert_eq!(escape_key("my host"), "my\\ host");
+        assert_eq!(escape_key("a,b=c"), "a\\,b\\=c");
+    }
+
+    #[test]
+    fn test_write_field_value_rejects_non_finite_gauge() {
+        let mut buf = Vec::new();
+        let err = write_field_value(&mut buf, Value::Gauge(f64::NAN)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_write_field_value_uses_unsigned_suffix_above_i64_max() {
+        let mut buf = Vec::new();
+        write_field_value(&mut buf, Value::Counter(u64::MAX)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), format!("{}u", u64::MAX));
+
+        let mut buf = Vec::new();
+        write_field_value(&mut buf, Value::Counter(42)).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "42i");
+    }
+}
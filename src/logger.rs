@@ -0,0 +1,250 @@
+//! # Logging
+//!
+//! `CollectdLoggerBuilder` wires the `log` crate's macros (`info!`, `error!`, ...) up to collectd's
+//! own logging facility (`collectd_log`), so plugin authors can use familiar logging macros instead
+//! of calling into collectd directly. Filtering works like `env_logger`: a global default level,
+//! overridable per module so a noisy dependency can be quieted without silencing the plugin's own
+//! log output, and (behind the `regex` feature) an allow/deny regex on the formatted message for
+//! routing by content rather than just module and severity.
+
+use crate::{collectd_log, LogLevel};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+/// Builds a [`log::Log`] implementation that forwards to collectd's logging facility.
+///
+/// ```no_run
+/// use collectd_plugin::CollectdLoggerBuilder;
+/// use log::LevelFilter;
+///
+/// CollectdLoggerBuilder::new()
+///     .filter_level(LevelFilter::Info)
+///     .filter_module("hyper", LevelFilter::Warn)
+///     .try_init()
+///     .expect("really the only thing that should create a logger");
+/// ```
+#[derive(Debug)]
+pub struct CollectdLoggerBuilder {
+    prefix: Option<String>,
+    default_level: LevelFilter,
+    module_filters: Vec<(String, LevelFilter)>,
+    #[cfg(feature = "regex")]
+    message_allow: Option<Regex>,
+    #[cfg(feature = "regex")]
+    message_deny: Option<Regex>,
+}
+
+impl Default for CollectdLoggerBuilder {
+    fn default() -> Self {
+        CollectdLoggerBuilder {
+            prefix: None,
+            // `log::LevelFilter` has no `Default` impl, so this has to be spelled out. `Trace`
+            // matches the crate's pre-existing behavior of forwarding everything to collectd
+            // until a plugin narrows it with `filter_level`.
+            default_level: LevelFilter::Trace,
+            module_filters: Vec::new(),
+            #[cfg(feature = "regex")]
+            message_allow: None,
+            #[cfg(feature = "regex")]
+            message_deny: None,
+        }
+    }
+}
+
+impl CollectdLoggerBuilder {
+    pub fn new() -> Self {
+        CollectdLoggerBuilder::default()
+    }
+
+    /// Prefixes every logged message with `M::name()`, so log output can be traced back to the
+    /// plugin that produced it.
+    pub fn prefix_plugin<M: crate::PluginManager>(mut self) -> Self {
+        self.prefix = Some(M::name().to_owned());
+        self
+    }
+
+    /// Sets the default level used for any module that doesn't match a more specific
+    /// [`filter_module`](Self::filter_module) directive.
+    pub fn filter_level(mut self, level: LevelFilter) -> Self {
+        self.default_level = level;
+        self
+    }
+
+    /// Overrides the level for a specific module path (and its submodules), independent of the
+    /// global default set via [`filter_level`](Self::filter_level). The longest matching prefix
+    /// wins, the same way `env_logger` resolves overlapping directives.
+    pub fn filter_module(mut self, module: impl Into<String>, level: LevelFilter) -> Self {
+        self.module_filters.push((module.into(), level));
+        self
+    }
+
+    /// Parses an `env_logger`-style filter spec such as `"mycrate=debug,hyper=warn"`. An entry
+    /// without a module (just a bare level, e.g. `"debug"`) sets the global default level; entries
+    /// of the form `module=level` are equivalent to calling
+    /// [`filter_module`](Self::filter_module) directly. Entries that can't be parsed are skipped.
+    pub fn parse_filters(mut self, spec: &str) -> Self {
+        for directive in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    if let Ok(level) = level.parse() {
+                        self = self.filter_module(module, level);
+                    }
+                }
+                None => {
+                    if let Ok(level) = directive.parse() {
+                        self = self.filter_level(level);
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    /// Only forwards messages whose formatted text matches `re`. Combined with
+    /// [`deny_message_matching`](Self::deny_message_matching), a message must match the allow
+    /// regex (if set) and not match the deny regex (if set) to be forwarded.
+    #[cfg(feature = "regex")]
+    pub fn allow_message_matching(mut self, re: Regex) -> Self {
+        self.message_allow = Some(re);
+        self
+    }
+
+    /// Suppresses messages whose formatted text matches `re`, even if they pass level and module
+    /// filtering.
+    #[cfg(feature = "regex")]
+    pub fn deny_message_matching(mut self, re: Regex) -> Self {
+        self.message_deny = Some(re);
+        self
+    }
+
+    /// Builds the logger and installs it as the global `log` logger.
+    pub fn try_init(self) -> Result<(), SetLoggerError> {
+        let max_level = self
+            .module_filters
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default_level, std::cmp::max);
+
+        log::set_boxed_logger(Box::new(CollectdLogger {
+            prefix: self.prefix,
+            default_level: self.default_level,
+            module_filters: self.module_filters,
+            #[cfg(feature = "regex")]
+            message_allow: self.message_allow,
+            #[cfg(feature = "regex")]
+            message_deny: self.message_deny,
+        }))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+struct CollectdLogger {
+    prefix: Option<String>,
+    default_level: LevelFilter,
+    module_filters: Vec<(String, LevelFilter)>,
+    #[cfg(feature = "regex")]
+    message_allow: Option<Regex>,
+    #[cfg(feature = "regex")]
+    message_deny: Option<Regex>,
+}
+
+impl CollectdLogger {
+    /// Finds the level filter for `target`, preferring the longest matching module prefix over
+    /// the global default (mirroring `env_logger`'s directive resolution).
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.module_filters
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default_level, |(_, level)| *level)
+    }
+}
+
+impl Log for CollectdLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = match &self.prefix {
+            Some(prefix) => format!("{}: {}", prefix, record.args()),
+            None => record.args().to_string(),
+        };
+
+        #[cfg(feature = "regex")]
+        {
+            if let Some(allow) = &self.message_allow {
+                if !allow.is_match(&message) {
+                    return;
+                }
+            }
+            if let Some(deny) = &self.message_deny {
+                if deny.is_match(&message) {
+                    return;
+                }
+            }
+        }
+
+        collectd_log(LogLevel::from(record.level()), &message);
+    }
+
+    fn flush(&self) {}
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warning,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug | log::Level::Trace => LogLevel::Debug,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_module_wins_over_default() {
+        let logger = CollectdLogger {
+            prefix: None,
+            default_level: LevelFilter::Warn,
+            module_filters: vec![("hyper".to_owned(), LevelFilter::Off)],
+            #[cfg(feature = "regex")]
+            message_allow: None,
+            #[cfg(feature = "regex")]
+            message_deny: None,
+        };
+
+        assert_eq!(logger.level_for("hyper"), LevelFilter::Off);
+        assert_eq!(logger.level_for("hyper::client"), LevelFilter::Off);
+        assert_eq!(logger.level_for("mycrate"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        let builder = CollectdLoggerBuilder::new().parse_filters("info,hyper=warn,mycrate::io=debug");
+
+        assert_eq!(builder.default_level, LevelFilter::Info);
+        assert_eq!(
+            builder.module_filters,
+            vec![
+                ("hyper".to_owned(), LevelFilter::Warn),
+                ("mycrate::io".to_owned(), LevelFilter::Debug),
+            ]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! # Self-instrumentation
+//!
+//! [`Instrumented`] wraps a [`Plugin`] and times every `write_values`/`read_values` call with an
+//! HDR histogram, the way influx-writer tracks its own per-interval latency percentiles. Each
+//! thread records straight into its own histogram (so the hot path only contends with a flush in
+//! progress, never with other recording threads); on a fixed interval every thread's histogram is
+//! drained into one combined histogram,
+//! turned into p50/p90/p99 latency and call-count `Value`s, and dispatched back into collectd so
+//! it flows through whatever write plugins are already loaded. Each metric is submitted under a
+//! universal, schema-agnostic `type_` (`gauge`/`derive`, matching the `Value` variant used) with
+//! a distinct `type_instance`, since collectd ties value names/counts to a registered `type_`
+//! rather than letting a single submission carry arbitrarily-named values.
+//!
+//! Draining happens on every flush regardless of how many calls a given thread made in between —
+//! a `read_values` plugin that's called once per interval still needs its single sample to show up
+//! immediately, rather than waiting for enough calls to accumulate to justify a merge.
+
+use crate::api::{Value, ValueList, ValueListBuilder};
+use crate::{Plugin, PluginCapabilities};
+use hdrhistogram::Histogram;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+type LocalHistogram = Arc<Mutex<Histogram<u64>>>;
+
+thread_local! {
+    static WRITE_LOCAL: RefCell<HashMap<usize, LocalHistogram>> = RefCell::new(HashMap::new());
+    static READ_LOCAL: RefCell<HashMap<usize, LocalHistogram>> = RefCell::new(HashMap::new());
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new(3).expect("hardcoded HDR histogram precision is valid")
+}
+
+/// Returns (creating and registering if necessary) the calling thread's histogram for this
+/// `Instrumented` instance, identified by its address.
+fn local_histogram(
+    local: &'static std::thread::LocalKey<RefCell<HashMap<usize, LocalHistogram>>>,
+    registry: &Mutex<Vec<LocalHistogram>>,
+    instance_key: usize,
+) -> LocalHistogram {
+    local.with(|map| {
+        map.borrow_mut()
+            .entry(instance_key)
+            .or_insert_with(|| {
+                let histogram = Arc::new(Mutex::new(new_histogram()));
+                registry.lock().unwrap().push(histogram.clone());
+                histogram
+            })
+            .clone()
+    })
+}
+
+/// Wraps `P`, timing every `write_values`/`read_values` call in microseconds and periodically
+/// re-emitting p50/p90/p99 latency and call counts as synthetic `ValueList`s (one per metric)
+/// under the plugin instances `<plugin_instance>-write` and `<plugin_instance>-read`.
+pub struct Instrumented<P: Plugin> {
+    inner: P,
+    plugin_instance: String,
+    flush_interval: Duration,
+    write_registry: Mutex<Vec<LocalHistogram>>,
+    read_registry: Mutex<Vec<LocalHistogram>>,
+    write_calls: AtomicU64,
+    read_calls: AtomicU64,
+    last_flush: Mutex<Instant>,
+}
+
+impl<P: Plugin> Instrumented<P> {
+    /// Wraps `inner`, emitting self-metrics under `<plugin_instance>-write`/`-read` every
+    /// `flush_interval`.
+    pub fn new(inner: P, plugin_instance: impl Into<String>) -> Self {
+        Instrumented {
+            inner,
+            plugin_instance: plugin_instance.into(),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            write_registry: Mutex::new(Vec::new()),
+            read_registry: Mutex::new(Vec::new()),
+            write_calls: AtomicU64::new(0),
+            read_calls: AtomicU64::new(0),
+            last_flush: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Overrides how often aggregated latencies are re-emitted into collectd. Defaults to 10
+    /// seconds.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Identifies this instance's thread-local histograms; a plugin only ever has one
+    /// `Instrumented` wrapper alive for its lifetime, so the wrapper's address is a stable key.
+    fn instance_key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Records `elapsed` straight into the calling thread's histogram for this instance. No lock
+    /// is taken except the (uncontended, thread-owned) one guarding that histogram, so this never
+    /// blocks on other threads or on a flush in progress for long.
+    fn record(
+        local: &'static std::thread::LocalKey<RefCell<HashMap<usize, LocalHistogram>>>,
+        registry: &Mutex<Vec<LocalHistogram>>,
+        instance_key: usize,
+        calls: &AtomicU64,
+        elapsed: Duration,
+    ) {
+        // HDR histograms can't record 0; round sub-microsecond calls up to 1us rather than
+        // silently dropping them (and skewing the percentiles low by undercounting fast calls).
+        let micros = elapsed.as_micros().min(u128::from(u64::MAX)).max(1) as u64;
+        calls.fetch_add(1, Ordering::Relaxed);
+
+        let histogram = local_histogram(local, registry, instance_key);
+        let _ = histogram.lock().unwrap().record(micros);
+    }
+
+    /// Emits the aggregated latency/throughput metrics as a synthetic `ValueList` if
+    /// `flush_interval` has elapsed since the last emission.
+    fn maybe_flush(&self) -> Result<(), Box<dyn error::Error>> {
+        {
+            let mut last_flush = self.last_flush.lock().unwrap();
+            if last_flush.elapsed() < self.flush_interval {
+                return Ok(());
+            }
+            *last_flush = Instant::now();
+        }
+
+        self.flush_metrics("write", &self.write_registry, &self.write_calls)?;
+        self.flush_metrics("read", &self.read_registry, &self.read_calls)?;
+        Ok(())
+    }
+
+    fn flush_metrics(
+        &self,
+        kind: &str,
+        registry: &Mutex<Vec<LocalHistogram>>,
+        calls: &AtomicU64,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let mut combined = new_histogram();
+        for local in registry.lock().unwrap().iter() {
+            let mut local = local.lock().unwrap();
+            combined
+                .add(&*local)
+                .expect("local and combined histograms share the same precision/range");
+            local.reset();
+        }
+
+        let count = calls.swap(0, Ordering::Relaxed);
+        if combined.len() == 0 && count == 0 {
+            return Ok(());
+        }
+
+        let plugin_instance = format!("{}-{}", self.plugin_instance, kind);
+
+        // Collectd ties value names/counts to a registered `type_` (types.db schema) rather than
+        // letting a ValueList carry arbitrary per-value names, so each metric is its own
+        // submission under a universal, schema-agnostic type (`gauge`/`derive`, matching the
+        // `Value` variant used) with a `type_instance` distinguishing the metric.
+        for (type_instance, value) in [
+            ("p50_us", Value::Gauge(combined.value_at_quantile(0.50) as f64)),
+            ("p90_us", Value::Gauge(combined.value_at_quantile(0.90) as f64)),
+            ("p99_us", Value::Gauge(combined.value_at_quantile(0.99) as f64)),
+        ] {
+            ValueListBuilder::new("instrumentation", "gauge")
+                .values(&[value])
+                .plugin_instance(plugin_instance.clone())
+                .type_instance(type_instance)
+                .submit()?;
+        }
+
+        ValueListBuilder::new("instrumentation", "derive")
+            .values(&[Value::Derive(count as i64)])
+            .plugin_instance(plugin_instance)
+            .type_instance("calls")
+            .submit()?;
+
+        Ok(())
+    }
+}
+
+impl<P: Plugin> Plugin for Instrumented<P> {
+    fn capabilities(&self) -> PluginCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn write_values(&self, list: ValueList<'_>) -> Result<(), Box<dyn error::Error>> {
+        let start = Instant::now();
+        let result = self.inner.write_values(list);
+        Self::record(
+            &WRITE_LOCAL,
+            &self.write_registry,
+            self.instance_key(),
+            &self.write_calls,
+            start.elapsed(),
+        );
+        self.maybe_flush()?;
+        result
+    }
+
+    fn read_values(&self) -> Result<(), Box<dyn error::Error>> {
+        let start = Instant::now();
+        let result = self.inner.read_values();
+        Self::record(
+            &READ_LOCAL,
+            &self.read_registry,
+            self.instance_key(),
+            &self.read_calls,
+            start.elapsed(),
+        );
+        self.maybe_flush()?;
+        result
+    }
+}
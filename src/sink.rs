@@ -0,0 +1,458 @@
+//! # `WriteSink`
+//!
+//! Write plugins are invoked by collectd on its own dispatch thread, so blocking there (a slow
+//! `write()`, a stalled TCP connection) stalls collectd itself. `WriteSink` moves the actual I/O
+//! onto a background thread: `push` only has to hand a serialized point to a bounded channel, and
+//! a dedicated thread batches those points and flushes them to the underlying writer on a timer.
+//!
+//! If the underlying connection is down, batches are spooled to a capped local file instead of
+//! being dropped on the floor, and replayed once a new connection can be established. This mirrors
+//! the channel-plus-background-thread design influx-writer uses for high-throughput metric
+//! shipping.
+//!
+//! `push` batches raw bytes by straight concatenation: it does not insert a record separator
+//! between pushed runs. Callers pushing multiple records (e.g. repeated
+//! [`to_line_protocol`](crate::format::to_line_protocol) calls) must supply their own delimiter,
+//! such as a trailing `\n` per record, or batched records will run together on the wire.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{error, warn};
+
+const DEFAULT_BATCH_SIZE: usize = 128;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_SPOOL_BYTES: u64 = 16 * 1024 * 1024;
+const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+enum Command {
+    Push(Vec<u8>),
+    Flush,
+    Shutdown,
+}
+
+/// Builds a [`WriteSink`]. `connect` is called on the background thread whenever a connection is
+/// needed, both on startup and after a write failure, so it should produce a fresh `W` each time
+/// (e.g. `TcpStream::connect`).
+pub struct WriteSinkBuilder<F> {
+    connect: F,
+    batch_size: usize,
+    flush_interval: Duration,
+    spool_path: Option<PathBuf>,
+    max_spool_bytes: u64,
+    channel_capacity: usize,
+}
+
+impl<F, W> WriteSinkBuilder<F>
+where
+    F: FnMut() -> io::Result<W> + Send + 'static,
+    W: Write + Send + 'static,
+{
+    pub fn new(connect: F) -> Self {
+        WriteSinkBuilder {
+            connect,
+            batch_size: DEFAULT_BATCH_SIZE,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            spool_path: None,
+            max_spool_bytes: DEFAULT_MAX_SPOOL_BYTES,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+        }
+    }
+
+    /// Number of pushed points to accumulate before flushing, even if `flush_interval` hasn't
+    /// elapsed yet.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Maximum time to hold a partial batch before flushing it anyway.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Where to spool batches that couldn't be written while the connection is down. Without a
+    /// spool path, unsent batches are dropped rather than held in memory indefinitely.
+    pub fn spool_path(mut self, spool_path: impl Into<PathBuf>) -> Self {
+        self.spool_path = Some(spool_path.into());
+        self
+    }
+
+    /// Rolling capacity cap for the spool file; once exceeded the oldest spooled batches are
+    /// dropped to make room for new ones.
+    pub fn max_spool_bytes(mut self, max_spool_bytes: u64) -> Self {
+        self.max_spool_bytes = max_spool_bytes;
+        self
+    }
+
+    /// Bound on the number of pushed points the channel will hold before `push` starts dropping
+    /// new points rather than blocking the caller (collectd's dispatch thread).
+    pub fn channel_capacity(mut self, channel_capacity: usize) -> Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    pub fn build(self) -> WriteSink {
+        let (sender, receiver) = mpsc::sync_channel(self.channel_capacity);
+        let worker = Worker {
+            connect: self.connect,
+            connection: None,
+            batch_size: self.batch_size,
+            flush_interval: self.flush_interval,
+            spool_path: self.spool_path,
+            max_spool_bytes: self.max_spool_bytes,
+            backoff: INITIAL_BACKOFF,
+            pending: Vec::new(),
+        };
+        let handle = thread::Builder::new()
+            .name("collectd-write-sink".into())
+            .spawn(move || worker.run(receiver))
+            .expect("failed to spawn write-sink background thread");
+
+        WriteSink {
+            sender,
+            handle: Some(handle),
+        }
+    }
+}
+
+/// A non-blocking sink for write plugins: `push` enqueues bytes and returns immediately, while a
+/// background thread owns the actual connection, batching, and disk fallback.
+pub struct WriteSink {
+    sender: SyncSender<Command>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl WriteSink {
+    /// Starts building a sink. `connect` is used to (re-)establish the underlying writer.
+    pub fn builder<F, W>(connect: F) -> WriteSinkBuilder<F>
+    where
+        F: FnMut() -> io::Result<W> + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        WriteSinkBuilder::new(connect)
+    }
+
+    /// Hands a serialized point to the background thread. Never blocks: the channel is bounded,
+    /// so if the background thread is behind (or gone) the point is dropped rather than stalling
+    /// collectd's dispatch thread.
+    ///
+    /// `bytes` is appended to the current batch as-is, with no delimiter inserted between pushes —
+    /// callers that push multiple records must terminate each one themselves (e.g. with a
+    /// trailing `\n`, as [`to_line_protocol`](crate::format::to_line_protocol) does).
+    pub fn push(&self, bytes: impl Into<Vec<u8>>) {
+        match self.sender.try_send(Command::Push(bytes.into())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("write-sink channel is full, dropping point");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("write-sink background thread is gone, dropping point");
+            }
+        }
+    }
+
+    /// Requests an out-of-band flush of whatever is currently batched. Does not wait for the
+    /// flush to complete.
+    pub fn flush(&self) {
+        let _ = self.sender.send(Command::Flush);
+    }
+}
+
+impl Drop for WriteSink {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Worker<F, W> {
+    connect: F,
+    connection: Option<W>,
+    batch_size: usize,
+    flush_interval: Duration,
+    spool_path: Option<PathBuf>,
+    max_spool_bytes: u64,
+    backoff: Duration,
+    pending: Vec<u8>,
+}
+
+impl<F, W> Worker<F, W>
+where
+    F: FnMut() -> io::Result<W>,
+    W: Write,
+{
+    fn run(mut self, receiver: mpsc::Receiver<Command>) {
+        let mut batched = 0usize;
+        loop {
+            match receiver.recv_timeout(self.flush_interval) {
+                Ok(Command::Push(bytes)) => {
+                    self.pending.extend_from_slice(&bytes);
+                    batched += 1;
+                    if batched >= self.batch_size {
+                        self.flush_batch();
+                        batched = 0;
+                    }
+                }
+                Ok(Command::Flush) => {
+                    self.flush_batch();
+                    batched = 0;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.flush_batch();
+                    batched = 0;
+                }
+                Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                    self.flush_batch();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn flush_batch(&mut self) {
+        if self.connection.is_none() {
+            self.reconnect();
+        }
+
+        // Replay anything spooled from an earlier outage before sending the current batch, so
+        // points go out in time order rather than newest-first. This runs even if `pending` is
+        // empty, otherwise a reconnect with no new points would strand spooled data forever.
+        if self.connection.is_some() {
+            self.replay_spool();
+        }
+
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let write_result = match self.connection.as_mut() {
+            Some(conn) => conn.write_all(&self.pending).and_then(|()| conn.flush()),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "no connection")),
+        };
+
+        match write_result {
+            Ok(()) => {
+                self.backoff = INITIAL_BACKOFF;
+                self.pending.clear();
+            }
+            Err(e) => {
+                warn!("write-sink flush failed, spooling batch: {}", e);
+                self.connection = None;
+                self.spool(&self.pending.clone());
+                self.pending.clear();
+                thread::sleep(self.backoff);
+                self.backoff = std::cmp::min(self.backoff * 2, MAX_BACKOFF);
+            }
+        }
+    }
+
+    fn reconnect(&mut self) {
+        match (self.connect)() {
+            Ok(conn) => self.connection = Some(conn),
+            Err(e) => warn!("write-sink could not (re)connect: {}", e),
+        }
+    }
+
+    /// Appends `bytes` as a length-prefixed record to the spool file, dropping the oldest records
+    /// first if doing so would exceed `max_spool_bytes`.
+    fn spool(&self, bytes: &[u8]) {
+        let Some(path) = self.spool_path.as_ref() else {
+            return;
+        };
+
+        // The incoming record also carries its 8-byte length prefix.
+        if let Err(e) = self.enforce_spool_cap(path, bytes.len() as u64 + 8) {
+            error!("write-sink could not enforce spool cap: {}", e);
+        }
+
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| {
+                f.write_all(&(bytes.len() as u64).to_le_bytes())?;
+                f.write_all(bytes)
+            });
+
+        if let Err(e) = result {
+            error!("write-sink could not spool batch to {:?}: {}", path, e);
+        }
+    }
+
+    fn enforce_spool_cap(&self, path: &PathBuf, incoming: u64) -> io::Result<()> {
+        let current = match File::open(path) {
+            Ok(f) => f.metadata()?.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if current + incoming <= self.max_spool_bytes {
+            return Ok(());
+        }
+
+        // Drop the oldest records until the new batch fits, by replaying the file forward and
+        // rewriting everything from the first record that survives.
+        let records = read_spool_records(path)?;
+        let mut kept: Vec<Vec<u8>> = Vec::new();
+        let mut kept_bytes = incoming;
+        for record in records.into_iter().rev() {
+            let size = record.len() as u64 + 8;
+            if kept_bytes + size > self.max_spool_bytes {
+                break;
+            }
+            kept_bytes += size;
+            kept.push(record);
+        }
+        kept.reverse();
+
+        let mut file = File::create(path)?;
+        for record in kept {
+            file.write_all(&(record.len() as u64).to_le_bytes())?;
+            file.write_all(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Replays and clears the spool file once the connection is healthy again.
+    fn replay_spool(&mut self) {
+        let Some(path) = self.spool_path.clone() else {
+            return;
+        };
+
+        let records = match read_spool_records(&path) {
+            Ok(records) if !records.is_empty() => records,
+            Ok(_) => return,
+            Err(e) => {
+                error!("write-sink could not read spool file {:?}: {}", path, e);
+                return;
+            }
+        };
+
+        let mut broken = false;
+        {
+            let conn = match self.connection.as_mut() {
+                Some(conn) => conn,
+                None => return,
+            };
+
+            for record in &records {
+                if let Err(e) = conn.write_all(record) {
+                    warn!("write-sink failed replaying spool, will retry later: {}", e);
+                    broken = true;
+                    break;
+                }
+            }
+            if !broken {
+                let _ = conn.flush();
+            }
+        }
+
+        if broken {
+            // The connection is presumably broken; drop it so the next flush reconnects (and
+            // replays from the start) instead of repeatedly failing on a dead writer.
+            self.connection = None;
+            return;
+        }
+
+        if let Err(e) = File::create(&path) {
+            error!("write-sink could not truncate spool file {:?}: {}", path, e);
+        }
+    }
+}
+
+fn read_spool_records(path: &PathBuf) -> io::Result<Vec<Vec<u8>>> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut records = Vec::new();
+    loop {
+        let mut len_buf = [0u8; 8];
+        match file.read_exact(&mut len_buf) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let len = u64::from_le_bytes(len_buf) as usize;
+        let mut record = vec![0u8; len];
+        file.read_exact(&mut record)?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().flush()
+        }
+    }
+
+    #[test]
+    fn flushes_pushed_batches() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Cursor::new(Vec::new()))));
+        let sink_buf = buf.clone();
+        let sink = WriteSinkBuilder::new(move || Ok(sink_buf.clone()))
+            .batch_size(2)
+            .flush_interval(Duration::from_millis(50))
+            .build();
+
+        sink.push(b"a".to_vec());
+        sink.push(b"b".to_vec());
+        sink.flush();
+        drop(sink);
+
+        let written = buf.0.lock().unwrap().get_ref().clone();
+        assert_eq!(written, b"ab".to_vec());
+    }
+
+    #[test]
+    fn spool_round_trips_records() {
+        let dir = std::env::temp_dir().join(format!("write-sink-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+
+        let worker = Worker {
+            connect: || -> io::Result<Cursor<Vec<u8>>> { Ok(Cursor::new(Vec::new())) },
+            connection: None,
+            batch_size: 1,
+            flush_interval: Duration::from_secs(1),
+            spool_path: Some(dir.clone()),
+            max_spool_bytes: DEFAULT_MAX_SPOOL_BYTES,
+            backoff: INITIAL_BACKOFF,
+            pending: Vec::new(),
+        };
+
+        worker.spool(b"one");
+        worker.spool(b"two");
+
+        let records = read_spool_records(&dir).unwrap();
+        assert_eq!(records, vec![b"one".to_vec(), b"two".to_vec()]);
+
+        let _ = std::fs::remove_file(&dir);
+    }
+}